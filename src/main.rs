@@ -21,6 +21,8 @@ extern crate minidom;
 extern crate rayon;
 extern crate regex;
 extern crate rexiv2;
+extern crate serde;
+extern crate serde_json;
 
 use anyhow::Result;
 use lazy_static::lazy_static;
@@ -32,6 +34,7 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::process::Command;
 
 const RDF_NS: &'static str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
 
@@ -40,14 +43,62 @@ struct ImgInfo {
     path: std::path::PathBuf,
     sidecar_path: Option<std::path::PathBuf>,
     date: chrono::NaiveDateTime,
+    date_source: DateSource,
     model: String,
 }
 
+// Records which tag (or fallback) a file's date was resolved from, so a future
+// verbose mode can report on images whose EXIF data was incomplete.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum DateSource {
+    ExifDateTimeOriginal,
+    ExifDateTime,
+    ExifTool,
+    FileModified,
+}
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 enum ImgRole {
     Raw,
     CameraJPG,
+    Heif,
+    Png,
+    Video,
+}
+
+// Single source of truth for which extensions the tool recognizes, shared by
+// scan_for_images' filter and ImgInfo::role() so the two can't drift apart.
+const EXTENSION_ROLES: &[(&str, ImgRole)] = &[
+    ("nef", ImgRole::Raw),
+    ("raf", ImgRole::Raw),
+    ("cr2", ImgRole::Raw),
+    ("cr3", ImgRole::Raw),
+    ("crw", ImgRole::Raw),
+    ("arw", ImgRole::Raw),
+    ("sr2", ImgRole::Raw),
+    ("srf", ImgRole::Raw),
+    ("orf", ImgRole::Raw),
+    ("rw2", ImgRole::Raw),
+    ("dng", ImgRole::Raw),
+    ("pef", ImgRole::Raw),
+    ("3fr", ImgRole::Raw),
+    ("iiq", ImgRole::Raw),
+    ("nrw", ImgRole::Raw),
+    ("mrw", ImgRole::Raw),
+    ("erf", ImgRole::Raw),
+    ("kdc", ImgRole::Raw),
+    ("dcr", ImgRole::Raw),
+    ("mos", ImgRole::Raw),
+    ("jpg", ImgRole::CameraJPG),
+    ("heic", ImgRole::Heif),
+    ("heif", ImgRole::Heif),
+    ("png", ImgRole::Png),
+    ("mov", ImgRole::Video),
+    ("mp4", ImgRole::Video),
+];
+
+fn role_for_extension(extension: &str) -> Option<ImgRole> {
+    EXTENSION_ROLES.iter().find(|(ext, _)| *ext == extension).map(|(_, role)| *role)
 }
 
 #[derive(Debug)]
@@ -63,12 +114,7 @@ impl ImgInfo {
 
     fn role(&self) -> Option<ImgRole> {
         let extension = self.path.extension().unwrap().to_str().unwrap().to_ascii_lowercase();
-        match extension.as_str() {
-            "nef" => Some(ImgRole::Raw),
-            "raf" => Some(ImgRole::Raw),
-            "jpg" => Some(ImgRole::CameraJPG),
-            _ => None
-        }
+        role_for_extension(&extension)
     }
 }
 
@@ -89,46 +135,59 @@ impl ImgGroup {
 
 
 
-fn scan_for_images(dir: &Path) -> std::io::Result<Vec<ImgInfo>> {
-    let mut entries: Vec<std::fs::DirEntry> = Vec:: new();
-    for entry in std::fs::read_dir(dir)? { 
+fn scan_for_images(dir: &Path, recursive: bool) -> std::io::Result<Vec<ImgInfo>> {
+    let mut entries: Vec<std::fs::DirEntry> = Vec::new();
+    collect_candidate_entries(dir, recursive, &mut entries)?;
+    let imgs: Vec<ImgInfo> = entries.par_iter().filter_map(|ref entry| {
+        match read_img(&entry) {
+            Ok(img) => Some(img),
+            Err(error) => {
+                let path = entry.path();
+                let path_str = path.to_str().unwrap();
+                eprintln!("Error reading image metatada from {}: {}. Skipping.", path_str, error);
+                None
+            }
+        }
+    }).collect();
+    Ok(imgs)
+}
+
+// Walks `dir` depth-first, collecting candidate image/video files into `entries`.
+// Dotfiles are always skipped; subdirectories are only descended into when `recursive`.
+fn collect_candidate_entries(dir: &Path, recursive: bool, entries: &mut Vec<std::fs::DirEntry>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
         let entry = entry?;
         if entry.path().file_name().unwrap().as_bytes()[0] == b'.' {
             continue;
         }
         if let Ok(file_type) = entry.file_type() {
             if file_type.is_dir() {
+                if recursive {
+                    collect_candidate_entries(&entry.path(), recursive, entries)?;
+                }
                 continue
             }
         }
         else {
             continue
         }
-        if let Some(ext) = entry.path().extension() {
-            if ext == "xmp"  {
-                continue;
-            }
+        let extension = entry.path().extension().map(|ext| ext.to_str().unwrap().to_ascii_lowercase());
+        match extension {
+            Some(ref ext) if role_for_extension(ext).is_some() => (),
+            _ => continue,
         }
         entries.push(entry);
     }
-    let imgs: Vec<ImgInfo> = entries.par_iter().filter_map(|ref entry| {
-        match read_img(&entry) {
-            Ok(img) => Some(img),
-            Err(error) => {
-                let path = entry.path();
-                let path_str = path.to_str().unwrap();
-                eprintln!("Error reading image metatada from {}: {}. Skipping.", path_str, error);
-                None
-            }
-        }
-    }).collect();
-    Ok(imgs)
+    Ok(())
 }
 
 fn build_imgs_groups(imgs: Vec<ImgInfo>) -> Vec<ImgGroup> {
     let mut img_group_map: HashMap<String, ImgGroup> = HashMap::new();
     for img in imgs {
-        let role = img.role().unwrap();
+        let role = match img.role() {
+            Some(role) => role,
+            None => continue,
+        };
         let key = img.base_path();
         let group = img_group_map.entry(key).or_insert(Default::default());
         group.members.insert(role, img);
@@ -139,16 +198,77 @@ fn build_imgs_groups(imgs: Vec<ImgInfo>) -> Vec<ImgGroup> {
 }
 
 
-fn read_img(entry: &std::fs::DirEntry) -> Result<ImgInfo, rexiv2::Rexiv2Error> {
+fn read_img(entry: &std::fs::DirEntry) -> Result<ImgInfo> {
     let date_tag = "Exif.Photo.DateTimeOriginal";
+    let fallback_date_tag = "Exif.Image.DateTime";
     let model_tag = "Exif.Image.Model";
-    let metadata = rexiv2::Metadata::new_from_path(entry.path())?;
-    let date_str = metadata.get_tag_string(date_tag)?;
-    let model = metadata.get_tag_string(model_tag).unwrap();
-    let date = chrono::NaiveDateTime::parse_from_str(&date_str, "%Y:%m:%d  %H:%M:%S").unwrap();
-    let sidecar_path = find_sidecar_path(&entry.path());
-    let img = ImgInfo { path: entry.path(), sidecar_path: sidecar_path, date: date, model: model };
-    Ok(img)
+    let path = entry.path();
+    let metadata = rexiv2::Metadata::new_from_path(&path).ok();
+    // Only a tag whose value actually parses counts as "found" — a present but
+    // garbled value (e.g. a reset-clock "0000:00:00 00:00:00") must fall
+    // through to the next source rather than aborting the whole read.
+    let tagged_date = metadata.as_ref().and_then(|metadata| {
+        [(date_tag, DateSource::ExifDateTimeOriginal), (fallback_date_tag, DateSource::ExifDateTime)]
+            .iter()
+            .find_map(|(tag, source)| {
+                let date_str = metadata.get_tag_string(tag).ok()?;
+                let date = chrono::NaiveDateTime::parse_from_str(&date_str, "%Y:%m:%d  %H:%M:%S").ok()?;
+                Some((date, *source))
+            })
+    });
+    let model = metadata.as_ref().and_then(|metadata| metadata.get_tag_string(model_tag).ok()).unwrap_or_default();
+
+    if let Some((date, date_source)) = tagged_date {
+        let sidecar_path = find_sidecar_path(&path);
+        return Ok(ImgInfo { path, sidecar_path, date, date_source, model });
+    }
+
+    if *EXIFTOOL_AVAILABLE {
+        if let Ok((date_str, exif_model)) = read_exiftool_metadata(&path) {
+            if let Ok(date) = chrono::NaiveDateTime::parse_from_str(&date_str, "%Y:%m:%d %H:%M:%S") {
+                let model = if model.is_empty() { exif_model } else { model };
+                let sidecar_path = find_sidecar_path(&path);
+                return Ok(ImgInfo { path, sidecar_path, date, date_source: DateSource::ExifTool, model });
+            }
+        }
+    }
+
+    read_img_from_mtime(path, model)
+}
+
+// Last-resort date source for files with no usable EXIF/exiftool date at all.
+fn read_img_from_mtime(path: std::path::PathBuf, model: String) -> Result<ImgInfo> {
+    let modified = std::fs::metadata(&path)?.modified()?;
+    let date = chrono::DateTime::<chrono::Local>::from(modified).naive_local();
+    let sidecar_path = find_sidecar_path(&path);
+    Ok(ImgInfo { path, sidecar_path, date, date_source: DateSource::FileModified, model })
+}
+
+lazy_static! {
+    static ref EXIFTOOL_AVAILABLE: bool = Command::new("exiftool").arg("-ver").output().is_ok();
+}
+
+// Falls back to the exiftool CLI for files rexiv2/libexiv2 can't parse (videos, exotic containers).
+fn read_exiftool_metadata(path: &Path) -> Result<(String, String)> {
+    let output = Command::new("exiftool")
+        .arg("-json")
+        .arg("-DateTimeOriginal")
+        .arg("-CreateDate")
+        .arg("-MediaCreateDate")
+        .arg("-Model")
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("exiftool exited with {} for {:?}", output.status, path));
+    }
+    let mut entries: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    let entry = entries.pop().ok_or_else(|| anyhow::anyhow!("exiftool returned no metadata for {:?}", path))?;
+    let date_str = ["DateTimeOriginal", "CreateDate", "MediaCreateDate"].iter()
+        .find_map(|field| entry.get(field).and_then(|v| v.as_str()))
+        .ok_or_else(|| anyhow::anyhow!("no creation date found via exiftool for {:?}", path))?
+        .to_owned();
+    let model = entry.get("Model").and_then(|v| v.as_str()).unwrap_or_default().to_owned();
+    Ok((date_str, model))
 }
 
 fn find_sidecar_path(img_path: &Path) -> Option<std::path::PathBuf> {
@@ -159,18 +279,23 @@ fn find_sidecar_path(img_path: &Path) -> Option<std::path::PathBuf> {
     return None;
 }
 
-fn reorganize_images(groups: &Vec<ImgGroup>, prefix: &str, dryrun: &bool, digits: Option<&u16>) -> Result<()> {
+fn reorganize_images(groups: &Vec<ImgGroup>, root: &Path, prefix: &str, dryrun: &bool, digits: Option<&u16>, into_tree: Option<&str>) -> Result<()> {
     let digits: usize = match digits {
         Some(d) => (*d).into(),
         _ => ((groups.len() + 1) as f32).log10().ceil() as usize
     };
     for (index, group) in (1..).zip(groups.iter()) {
+        let target_dir = match into_tree {
+            Some(pattern) => root.join(group.date().format(pattern).to_string()),
+            None => group.first_img().path.parent().unwrap().to_path_buf(),
+        };
         for img in group.members.values() {
-            let new_img_path = rename_file(&img.path, index, &img, &prefix, digits, dryrun)?;
+            let new_img_path = rename_file(&img.path, index, &img, &prefix, digits, &target_dir, dryrun)?;
             match &img.sidecar_path {
-                Some(path) => { 
-                    let new_sidecar_path = rename_file(path, index, &img, &prefix, digits, dryrun)?;
-                    rewrite_sidecar_file(&new_sidecar_path, &new_img_path, dryrun)?;
+                Some(path) => {
+                    let new_sidecar_path = rename_file(path, index, &img, &prefix, digits, &target_dir, dryrun)?;
+                    let relative_img_path = Path::new(new_img_path.file_name().unwrap());
+                    rewrite_sidecar_file(&new_sidecar_path, relative_img_path, dryrun)?;
                 },
                 None => (),
             }
@@ -179,8 +304,7 @@ fn reorganize_images(groups: &Vec<ImgGroup>, prefix: &str, dryrun: &bool, digits
     Ok(())
 }
 
-fn rename_file(src_path: &Path, index: usize, img: &ImgInfo, prefix: &str, index_digits: usize, dryrun: &bool) -> std::io::Result<std::path::PathBuf> {
-    let parent = src_path.parent().unwrap();
+fn rename_file(src_path: &Path, index: usize, img: &ImgInfo, prefix: &str, index_digits: usize, target_dir: &Path, dryrun: &bool) -> std::io::Result<std::path::PathBuf> {
     let date_str = img.date.format("%Y-%m-%d %H-%M-%S");
     let src_file_name = src_path.file_name().unwrap().to_str().unwrap();
     let src_file_name_parts: Vec<&str> = src_file_name.split('.').collect();
@@ -201,10 +325,16 @@ fn rename_file(src_path: &Path, index: usize, img: &ImgInfo, prefix: &str, index
     else {
         format!("{:0digits$} {} {} {}{}", index, date_str, prefix, img.model, suffix, digits=index_digits)
     };
-    let target_path = parent.join(&target_file_name);
+    let target_path = target_dir.join(&target_file_name);
     if &target_path != src_path {
-        println!("{}/{{{} -> {}}}", parent.to_str().unwrap(), src_file_name, target_file_name);
+        if target_dir == src_path.parent().unwrap() {
+            println!("{}/{{{} -> {}}}", target_dir.to_str().unwrap(), src_file_name, target_file_name);
+        }
+        else {
+            println!("{} -> {}", src_path.display(), target_path.display());
+        }
         if !dryrun {
+            std::fs::create_dir_all(target_dir)?;
             std::fs::rename(&src_path, &target_path)?
         }
     }
@@ -268,6 +398,10 @@ fn main() {
             .short('n')
             .long("dryrun")
             .help("Do not write out changes. Just show what would happen."))
+        .arg(clap::Arg::with_name("recursive")
+            .short('r')
+            .long("recursive")
+            .help("Descend into subdirectories instead of only scanning the top-level folder."))
         .arg(clap::Arg::with_name("prefix")
             .long("prefix")
             .takes_value(true)
@@ -277,6 +411,18 @@ fn main() {
             .help("Set number of digits in the counter of the generated image file names.")
             .takes_value(true)
             .value_parser(clap::value_parser!(u16).range(1..9)))
+        .arg(clap::Arg::with_name("threads")
+            .long("threads")
+            .help("Number of worker threads to use for scanning metadata. Defaults to the number of CPUs.")
+            .takes_value(true)
+            .value_parser(clap::value_parser!(usize)))
+        .arg(clap::Arg::with_name("into-tree")
+            .long("into-tree")
+            .help("Move each group into a dated subdirectory tree under PATH instead of renaming in place."))
+        .arg(clap::Arg::with_name("tree-pattern")
+            .long("tree-pattern")
+            .takes_value(true)
+            .help("strftime pattern for the subdirectory tree created by --into-tree. Defaults to \"%Y/%m/%d\"."))
         .get_matches();
     let path = Path::new(matches.value_of("PATH").unwrap());
     let default_prefix = match get_default_prefix(path) {
@@ -286,20 +432,29 @@ fn main() {
         },
     };
     let dryrun = matches.is_present("dryrun");
+    let recursive = matches.is_present("recursive");
     let prefix = matches.get_one("prefix").unwrap_or(&default_prefix);
     let digits: Option<&u16> = matches.get_one("digits");
+    let tree_pattern = matches.value_of("tree-pattern").unwrap_or("%Y/%m/%d");
+    let into_tree = if matches.is_present("into-tree") { Some(tree_pattern) } else { None };
     if dryrun {
         println!("Dry run. No changes will be written out.");
     }
+    let threads: Option<&usize> = matches.get_one("threads");
+    let mut pool_builder = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = threads {
+        pool_builder = pool_builder.num_threads(*threads);
+    }
+    pool_builder.build_global().expect("Error building thread pool");
     rexiv2::initialize().expect("Error initializing libexiv2");
-    let imgs: Vec<ImgInfo> = match scan_for_images(path) {
+    let imgs: Vec<ImgInfo> = match scan_for_images(path, recursive) {
         Ok(r)=> r,
         Err(error) => {
             panic!("Error: {:?}", error)
         },
     };
     let img_groups = build_imgs_groups(imgs);
-    match reorganize_images(&img_groups, &prefix, &dryrun, digits) {
+    match reorganize_images(&img_groups, path, &prefix, &dryrun, digits, into_tree) {
         Ok(_) => (),
         Err(error) => {
             panic!("Error: {:?}", error)